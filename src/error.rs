@@ -19,8 +19,8 @@
 use std::error::Error;
 use std::io;
 
-use method::Socks5Method;
-use reply::Socks5Reply;
+use crate::method::Socks5Method;
+use crate::reply::Socks5Reply;
 
 /// Generic function that builds a generic [io::Error]
 #[inline]
@@ -35,6 +35,17 @@ where
 pub fn invalid_host_length(length: usize) -> io::Error {
     other(format!("invalid hostname length: {}", length))
 }
+#[inline]
+pub fn invalid_credential_length(length: usize) -> io::Error {
+    other(format!(
+        "invalid username/password length (must be 1-255 bytes): {}",
+        length
+    ))
+}
+#[inline]
+pub fn auth_failed() -> io::Error {
+    other("server rejected username/password authentication")
+}
 
 #[inline]
 pub fn invalid_address_type(atyp: u8) -> io::Error {
@@ -75,3 +86,32 @@ pub fn reply_error(reply: Socks5Reply) -> io::Error {
     // Construct the error
     other(reply.to_string())
 }
+
+#[inline]
+pub fn socks4_request_rejected() -> io::Error {
+    other("SOCKS4 server rejected the request or it failed")
+}
+#[inline]
+pub fn socks4_identd_unreachable() -> io::Error {
+    other("SOCKS4 server could not reach the client's identd")
+}
+#[inline]
+pub fn socks4_identd_mismatch() -> io::Error {
+    other("SOCKS4 server's identd reported a different user-id")
+}
+#[inline]
+pub fn invalid_socks4_reply(cd: u8) -> io::Error {
+    other(format!("SOCKS4 server replied with unknown status code: {}", cd))
+}
+#[inline]
+pub fn socks4_ipv6_unsupported() -> io::Error {
+    other("SOCKS4/SOCKS4a do not support IPv6 destination addresses")
+}
+
+#[inline]
+pub fn tls_error<E>(err: E) -> io::Error
+where
+    E: Into<Box<Error + Send + Sync>>,
+{
+    other(err)
+}
@@ -16,25 +16,197 @@
     You should have received a copy of the GNU General Public License
     along with sonicks.  If not, see <https://www.gnu.org/licenses/>.
 */
+use std::collections::hash_map::DefaultHasher;
 use std::convert::{Into, TryInto};
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
 
+// `hyper::client::connect::Connect` (the Destination/Connected-based trait
+// from hyper 0.12) and tokio_tls both predate std futures, so all I/O here
+// stays on tokio 0.1/futures 0.1, and only the async/await bodies are
+// bridged to and from futures 0.1 via `futures::compat`
+use futures::compat::Future01CompatExt;
+use futures::future::{FutureExt, TryFutureExt};
 use hyper::client::connect::{Connect, Connected, Destination};
+use native_tls::TlsConnector as NativeTlsConnector;
 use tokio::io::{read_exact, write_all};
 use tokio::net::TcpStream;
 use tokio::prelude::*;
+use tokio_tls::{TlsConnector, TlsStream};
 
-use error;
-use method::Socks5Method;
-use reply::Socks5Reply;
+use crate::error;
+use crate::method::Socks5Method;
+use crate::reply::Socks5Reply;
+
+/// The stream returned once the SOCKS5 handshake completes: a bare TCP
+/// stream for `http` destinations, or a TCP stream wrapped in TLS for
+/// `https` destinations
+pub enum Transport {
+    /// Used for `http` destinations
+    Plain(TcpStream),
+    /// Used for `https` destinations
+    Tls(TlsStream<TcpStream>),
+}
+
+impl io::Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.read(buf),
+            Transport::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl io::Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.write(buf),
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(stream) => stream.flush(),
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Transport {}
+
+impl AsyncWrite for Transport {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            Transport::Plain(stream) => AsyncWrite::shutdown(stream),
+            Transport::Tls(stream) => AsyncWrite::shutdown(stream),
+        }
+    }
+}
+
+/// An address the SOCKS server reports back to the client, e.g. the
+/// BND.ADDR/BND.PORT fields of a SOCKS5 reply
+///
+/// Stored in the [Connected]'s extra data, retrievable from a response's
+/// `http::Extensions` once a request has completed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SocksAddr {
+    /// An IPv4 address and port
+    V4(Ipv4Addr, u16),
+    /// An IPv6 address and port
+    V6(Ipv6Addr, u16),
+    /// A domain name and port
+    Domain(String, u16),
+}
+
+impl SocksAddr {
+    /// Builds the SOCKS UDP request header (`RSV(2), FRAG, ATYP, DST.ADDR,
+    /// DST.PORT`) that must prefix each datagram sent to the relay address
+    /// returned by [Socks5hProxy::udp_associate]
+    ///
+    /// Fails if this is a [SocksAddr::Domain] whose name is longer than 255
+    /// bytes, since the length byte cannot represent it
+    pub fn to_udp_header(&self) -> io::Result<Vec<u8>> {
+        // RSV(2), FRAG(1) - fragmentation is not supported, so FRAG is always 0
+        let mut header = vec![0x00, 0x00, 0x00];
+        match self {
+            SocksAddr::V4(ip, port) => {
+                header.push(0x01);
+                header.extend_from_slice(&ip.octets());
+                header.extend_from_slice(&port.to_be_bytes());
+            }
+            SocksAddr::V6(ip, port) => {
+                header.push(0x04);
+                header.extend_from_slice(&ip.octets());
+                header.extend_from_slice(&port.to_be_bytes());
+            }
+            SocksAddr::Domain(name, port) => {
+                header.push(0x03);
+                // Ensure the name's length is compliant, the same way encode_host does
+                let length: u8 = match name.len().try_into() {
+                    // Zero-length or too long
+                    Ok(0) | Err(_) => return Err(error::invalid_host_length(name.len())),
+                    // Normal case
+                    Ok(length) => length,
+                };
+                header.push(length);
+                header.extend(name.bytes());
+                header.extend_from_slice(&port.to_be_bytes());
+            }
+        }
+        Ok(header)
+    }
+}
+
+/// The bound host half of a [SocksAddr], before its port has been read
+enum BoundHost {
+    V4(Ipv4Addr),
+    V6(Ipv6Addr),
+    Domain(String),
+}
+
+impl BoundHost {
+    /// Combines this host with a port to produce a full [SocksAddr]
+    fn with_port(self, port: u16) -> SocksAddr {
+        match self {
+            BoundHost::V4(ip) => SocksAddr::V4(ip, port),
+            BoundHost::V6(ip) => SocksAddr::V6(ip, port),
+            BoundHost::Domain(name) => SocksAddr::Domain(name, port),
+        }
+    }
+}
+
+/// Username/password credentials used for RFC 1929 sub-negotiation
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Credentials {
+    username: Vec<u8>,
+    password: Vec<u8>,
+}
+
+/// Controls how Tor stream isolation credentials are derived for each
+/// connection made through a [Socks5hProxy]
+///
+/// Tor places connections that authenticate with different username/password
+/// pairs on separate circuits, so deriving distinct, deterministic
+/// credentials per logical stream forces isolation, while reusing the same
+/// key reuses a circuit
+#[derive(Clone, Debug)]
+pub enum Isolation {
+    /// Derive the isolation key from the destination host of each connection,
+    /// so that distinct destinations are never placed on the same circuit
+    PerDestinationHost,
+}
+
+/// A pending SOCKS5 BIND operation
+///
+/// Holds the control connection to the proxy after the first BIND reply
+/// (which gives the address the client should advertise to the peer), until
+/// the second reply arrives announcing that the peer has connected
+pub struct BindAccept {
+    socket: TcpStream,
+}
+
+impl BindAccept {
+    /// Waits for the second BIND reply, fired once the peer connects to the
+    /// bound address, and returns the connected socket and the peer's address
+    pub async fn accept(self) -> io::Result<(TcpStream, SocksAddr)> {
+        Socks5hProxy::read_reply(self.socket).await
+    }
+}
 
 /// A SOCKS5 proxy
 ///
 /// Does DNS resolution remotely (socks5h)
+#[derive(Clone)]
 pub struct Socks5hProxy {
     addr: SocketAddr,
+    /// Username/password credentials to offer during the method handshake,
+    /// if username/password authentication should be used
+    auth: Option<Credentials>,
+    /// Tor stream isolation configuration, if isolation credentials should be
+    /// derived per connection instead of using `auth` directly
+    isolation: Option<Isolation>,
 }
 
 impl Socks5hProxy {
@@ -42,194 +214,487 @@ impl Socks5hProxy {
     const VER: u8 = 5;
     /// The reserved byte (must always be zero)
     const RSV: u8 = 0;
+    /// The version byte used by the RFC 1929 sub-negotiation
+    const AUTH_VER: u8 = 1;
+    /// The CONNECT command
+    const CMD_CONNECT: u8 = 0x01;
+    /// The BIND command
+    const CMD_BIND: u8 = 0x02;
+    /// The UDP ASSOCIATE command
+    const CMD_UDP_ASSOCIATE: u8 = 0x03;
     /// Constructor for the proxy
     ///
     /// # Parameters
     /// * `addr` - address of the proxy
     pub fn new(addr: SocketAddr) -> Self {
-        Socks5hProxy { addr }
+        Socks5hProxy { addr, auth: None, isolation: None }
+    }
+    /// Constructor for a proxy that authenticates using RFC 1929
+    /// username/password authentication
+    ///
+    /// # Parameters
+    /// * `addr` - address of the proxy
+    /// * `username` - username to authenticate with (1-255 bytes)
+    /// * `password` - password to authenticate with (1-255 bytes)
+    pub fn with_auth<U, P>(addr: SocketAddr, username: U, password: P) -> io::Result<Self>
+    where
+        U: Into<Vec<u8>>,
+        P: Into<Vec<u8>>,
+    {
+        let username = username.into();
+        let password = password.into();
+        if username.is_empty() || username.len() > 255 {
+            return Err(error::invalid_credential_length(username.len()));
+        }
+        if password.is_empty() || password.len() > 255 {
+            return Err(error::invalid_credential_length(password.len()));
+        }
+        Ok(Socks5hProxy {
+            addr,
+            auth: Some(Credentials { username, password }),
+            isolation: None,
+        })
+    }
+    /// Constructor for a proxy that isolates each connection onto its own Tor
+    /// circuit, per `isolation`
+    ///
+    /// # Parameters
+    /// * `addr` - address of the proxy
+    /// * `isolation` - how to derive the isolation key for each connection
+    pub fn with_isolation(addr: SocketAddr, isolation: Isolation) -> Self {
+        Socks5hProxy {
+            addr,
+            auth: None,
+            isolation: Some(isolation),
+        }
+    }
+    /// Connects to `dst` through the proxy, isolating the resulting stream
+    /// onto its own Tor circuit
+    ///
+    /// Identical `isolation_token`s reuse the same circuit; differing tokens
+    /// are forced onto separate circuits
+    /// # Parameters
+    /// * `dst` - the destination to connect to
+    /// * `isolation_token` - the isolation key for this stream
+    pub async fn connect_isolated(
+        &self,
+        dst: Destination,
+        isolation_token: &[u8],
+    ) -> io::Result<(Transport, Connected)> {
+        self.connect_with_credentials(dst, Some(Self::derive_isolation_credentials(isolation_token)))
+            .await
+    }
+    /// Deterministically derives a username/password pair from an isolation
+    /// key, so that identical keys always produce identical credentials
+    /// # Parameters
+    /// * `key` - the isolation key to derive credentials from
+    fn derive_isolation_credentials(key: &[u8]) -> Credentials {
+        // DefaultHasher::new() always starts from the same fixed state, so
+        // hashing the same key twice always yields the same digest
+        let mut username_hasher = DefaultHasher::new();
+        key.hash(&mut username_hasher);
+        let mut password_hasher = DefaultHasher::new();
+        // Salt the password hasher so username and password differ
+        b"sonicks-isolation-password".hash(&mut password_hasher);
+        key.hash(&mut password_hasher);
+        Credentials {
+            username: format!("{:016x}", username_hasher.finish()).into_bytes(),
+            password: format!("{:016x}", password_hasher.finish()).into_bytes(),
+        }
+    }
+    /// Connects to the proxy and completes method negotiation (and RFC 1929
+    /// sub-negotiation, if selected), returning the authenticated socket
+    /// # Parameters
+    /// * `auth_override` - credentials to use instead of `self.auth`, e.g. derived isolation credentials
+    async fn authenticate(&self, auth_override: Option<Credentials>) -> io::Result<TcpStream> {
+        let auth = auth_override.or_else(|| self.auth.clone());
+        // Determine which methods to offer based on whether credentials were configured
+        let methods = match auth {
+            Some(_) => vec![Socks5Method::NoAuthRequired, Socks5Method::UsernamePassword],
+            None => vec![Socks5Method::NoAuthRequired],
+        };
+        // Connect to the proxy
+        let socket = TcpStream::connect(&self.addr).compat().await?;
+        // Send supported methods and receive a method/version back
+        let (socket, version, method) = Self::method_handshake(socket, &methods).await?;
+        // Check the method and version
+        match (version, method) {
+            // No authentication
+            (Self::VER, Socks5Method::NoAuthRequired) => Ok(socket),
+            // Username/password authentication
+            (Self::VER, Socks5Method::UsernamePassword) => match auth {
+                Some(credentials) => Self::auth_handshake(socket, credentials).await,
+                // The server selected a method we never offered
+                None => Err(error::unsupported_method(Socks5Method::UsernamePassword)),
+            },
+            // TODO: GSSAPI
+            // Specific error for when no acceptable methods are returned
+            (Self::VER, Socks5Method::NoAcceptable) => Err(error::no_acceptable_methods()),
+            // Unsupported method
+            (Self::VER, method) => Err(error::unsupported_method(method)),
+            // Unsupported SOCKS version
+            (version, _) => Err(error::unsupported_version(version)),
+        }
+    }
+    /// Encodes a host into the ATYP/DST.ADDR portion of a SOCKS5 request,
+    /// as an IPv4, IPv6, or domain name address
+    /// # Parameters
+    /// * `request` - the request buffer to append to
+    /// * `host` - the host to encode
+    fn encode_host(request: &mut Vec<u8>, host: &str) -> io::Result<()> {
+        // Try to parse the host as an IP address
+        match IpAddr::from_str(host) {
+            // If the parsing works
+            Ok(ip) => match ip {
+                IpAddr::V4(ip) => {
+                    request.push(0x01);
+                    request.extend_from_slice(&ip.octets());
+                },
+                IpAddr::V6(ip) => {
+                    request.push(0x04);
+                    request.extend_from_slice(&ip.octets());
+                }
+            },
+            // If the parsing fails, treat the host as a hostname
+            Err(_) => {
+                request.push(0x03);
+                // Ensure the host's length is compliant
+                let length: u8 = match host.len().try_into() {
+                    // Zero-length or too long
+                    Ok(0) | Err(_) => return Err(error::invalid_host_length(host.len())),
+                    // Normal case
+                    Ok(length) => length,
+                };
+                // Add the length byte to the request
+                request.push(length);
+                // Add the hostname as bytes to the request
+                request.extend(host.bytes());
+            }
+        };
+        Ok(())
+    }
+    /// Builds a SOCKS5 request: `VER, CMD, RSV, ATYP, DST.ADDR, DST.PORT`
+    /// # Parameters
+    /// * `cmd` - the command byte (CONNECT, BIND, or UDP ASSOCIATE)
+    /// * `host` - the destination host to encode
+    /// * `port` - the destination port
+    fn command_request(cmd: u8, host: &str, port: u16) -> io::Result<Vec<u8>> {
+        let mut request: Vec<u8> = vec![Self::VER, cmd, Self::RSV];
+        Self::encode_host(&mut request, host)?;
+        request.extend_from_slice(&port.to_be_bytes());
+        Ok(request)
+    }
+    /// Reads a SOCKS5 reply (`VER, REP, RSV, ATYP, BND.ADDR, BND.PORT`) from
+    /// the socket, returning the socket and the decoded bound address
+    /// # Parameters
+    /// * `socket` - the socket to read the reply from
+    async fn read_reply(socket: TcpStream) -> io::Result<(TcpStream, SocksAddr)> {
+        let (socket, header) = read_exact(socket, [0x00; 4]).compat().await?;
+        // Check version
+        if header[0] != Self::VER {
+            return Err(error::unsupported_version(header[0]));
+        }
+        // Check the reply code
+        let reply = header[1].into();
+        if reply != Socks5Reply::Succeeded {
+            return Err(error::reply_error(reply));
+        }
+        // Check reserved byte
+        if header[2] != Self::RSV {
+            return Err(error::invalid_reserved(header[2]));
+        }
+        // Read in the bound address
+        let (socket, host) = match header[3] {
+            // IPv4
+            0x01 => {
+                let (socket, buf) = read_exact(socket, [0x00; 4]).compat().await?;
+                (socket, BoundHost::V4(Ipv4Addr::from(buf)))
+            }
+            // Hostname
+            0x03 => {
+                let (socket, len) = read_exact(socket, [0x00; 1]).compat().await?;
+                let (socket, buf) = read_exact(socket, vec![0x00; len[0] as usize]).compat().await?;
+                (socket, BoundHost::Domain(String::from_utf8_lossy(&buf).into_owned()))
+            }
+            // IPv6
+            0x04 => {
+                let (socket, buf) = read_exact(socket, [0x00; 16]).compat().await?;
+                (socket, BoundHost::V6(Ipv6Addr::from(buf)))
+            }
+            // Invalid values
+            atyp => return Err(error::invalid_address_type(atyp)),
+        };
+        // Read the bound port and combine it with the bound host
+        let (socket, port_buf) = read_exact(socket, [0x00; 2]).compat().await?;
+        let port = (u16::from(port_buf[0]) << 8) | u16::from(port_buf[1]);
+        Ok((socket, host.with_port(port)))
+    }
+    /// Performs a SOCKS5 BIND operation, so that a remote peer can connect to
+    /// the proxy on the client's behalf
+    ///
+    /// Returns the address the client should advertise to the peer, and a
+    /// [BindAccept] that resolves once the peer connects
+    /// # Parameters
+    /// * `dst` - the host/port the BIND request is made on behalf of
+    pub async fn bind(&self, dst: Destination) -> io::Result<(SocksAddr, BindAccept)> {
+        let socket = self.authenticate(None).await?;
+        let port = match dst.port() {
+            Some(port) => port,
+            None => match dst.scheme() {
+                "http" => 80,
+                "https" => 443,
+                scheme => return Err(error::unsupported_scheme(scheme)),
+            },
+        };
+        let request = Self::command_request(Self::CMD_BIND, dst.host(), port)?;
+        let (socket, _) = write_all(socket, request).compat().await?;
+        let (socket, addr) = Self::read_reply(socket).await?;
+        Ok((addr, BindAccept { socket }))
+    }
+    /// Performs a SOCKS5 UDP ASSOCIATE operation
+    ///
+    /// Returns the control connection (which must be kept open for the
+    /// duration of the UDP association) and the relay's UDP address, to
+    /// which datagrams prefixed with [SocksAddr::to_udp_header] can be sent
+    /// # Parameters
+    /// * `client_addr` - the address the client expects to send UDP datagrams from
+    pub async fn udp_associate(&self, client_addr: SocketAddr) -> io::Result<(TcpStream, SocksAddr)> {
+        let socket = self.authenticate(None).await?;
+        let request = Self::command_request(
+            Self::CMD_UDP_ASSOCIATE,
+            &client_addr.ip().to_string(),
+            client_addr.port(),
+        )?;
+        let (socket, _) = write_all(socket, request).compat().await?;
+        Self::read_reply(socket).await
     }
     /// Sends the initial method negotiation handshake
     /// # Parameters
     /// * `socket` - the socket to send and receive the handshake over
-    fn method_handshake(socket: TcpStream) -> impl Future<Item=(TcpStream, u8, Socks5Method), Error=io::Error> {
+    /// * `methods` - the methods to offer the server
+    async fn method_handshake(socket: TcpStream, methods: &[Socks5Method]) -> io::Result<(TcpStream, u8, Socks5Method)> {
+        // Build the method negotiation packet: VER, NMETHODS, METHODS...
+        let mut packet: Vec<u8> = vec![Self::VER, methods.len() as u8];
+        packet.extend(methods.iter().cloned().map(Into::into));
         // Send the supported methods
-        write_all(socket, [Self::VER, 1, Socks5Method::NoAuthRequired.into()])
-            // Remove the extra field
-            .and_then(|(socket, _)| read_exact(socket, [0x00; 2]))
-            .and_then(|(socket, method_resp)| future::ok((socket, method_resp[0], method_resp[1].into())))
+        let (socket, _) = write_all(socket, packet).compat().await?;
+        // Receive the server's chosen version/method
+        let (socket, response) = read_exact(socket, [0x00; 2]).compat().await?;
+        Ok((socket, response[0], response[1].into()))
+    }
+    /// Performs the RFC 1929 username/password sub-negotiation
+    /// # Parameters
+    /// * `socket` - the socket to send and receive the sub-negotiation over
+    /// * `credentials` - the username/password to authenticate with
+    async fn auth_handshake(socket: TcpStream, credentials: Credentials) -> io::Result<TcpStream> {
+        // Build the sub-negotiation packet: VER, ULEN, UNAME, PLEN, PASSWD
+        let mut packet: Vec<u8> = vec![Self::AUTH_VER, credentials.username.len() as u8];
+        packet.extend_from_slice(&credentials.username);
+        packet.push(credentials.password.len() as u8);
+        packet.extend_from_slice(&credentials.password);
+        let (socket, _) = write_all(socket, packet).compat().await?;
+        let (socket, status) = read_exact(socket, [0x00; 2]).compat().await?;
+        // status[0] is VER, status[1] is STATUS (0x00 == success)
+        if status[1] == 0x00 {
+            Ok(socket)
+        } else {
+            Err(error::auth_failed())
+        }
+    }
+    /// Connects to the destination through the proxy, using `auth_override`
+    /// in place of the configured `auth` if present
+    /// # Parameters
+    /// * `dst` - the destination to connect to
+    /// * `auth_override` - credentials to use instead of `self.auth`, e.g. derived isolation credentials
+    async fn connect_with_credentials(
+        &self,
+        dst: Destination,
+        auth_override: Option<Credentials>,
+    ) -> io::Result<(Transport, Connected)> {
+        // Determine whether the destination needs a TLS layer on top of the proxied stream
+        let wants_tls = dst.scheme() == "https";
+        let tls_host = dst.host().to_owned();
+        // Connect to the proxy and complete method/auth negotiation
+        let socket = self.authenticate(auth_override).await?;
+        // Get the port
+        let port = match dst.port() {
+            Some(port) => port,
+            // If the port is not specified, use the scheme to determine it
+            None => match dst.scheme() {
+                "http" => 80,
+                "https" => 443,
+                scheme => return Err(error::unsupported_scheme(scheme)),
+            },
+        };
+        // Write the connection request over the socket
+        let request = Self::command_request(Self::CMD_CONNECT, dst.host(), port)?;
+        let (socket, _) = write_all(socket, request).compat().await?;
+        // Read and decode the reply
+        let (socket, bound_addr) = Self::read_reply(socket).await?;
+        // Layer TLS over the socket if the destination is https
+        if wants_tls {
+            let connector = NativeTlsConnector::new().map_err(error::tls_error)?;
+            let stream = TlsConnector::from(connector)
+                .connect(&tls_host, socket)
+                .compat()
+                .await
+                .map_err(error::tls_error)?;
+            Ok((Transport::Tls(stream), Connected::new().extra(bound_addr)))
+        } else {
+            Ok((Transport::Plain(socket), Connected::new().extra(bound_addr)))
+        }
     }
-
 }
 
 impl Connect for Socks5hProxy {
+    /// The underlying transport is plain TCP for `http` destinations, or TCP
+    /// wrapped in TLS for `https` destinations
+    type Transport = Transport;
+    /// Uses `std::io::Error`
+    type Error = io::Error;
+    /// `hyper::client::connect::Connect` still speaks futures 0.1, so the
+    /// async/await body is boxed and bridged back to a futures 0.1 future
+    /// via `futures::compat`
+    type Future = Box<dyn Future<Item = (Transport, Connected), Error = io::Error> + Send>;
+    /// Connects to the destination through the proxy
+    /// # Parameters
+    /// * `dst` - the destination to connect to
+    fn connect(&self, dst: Destination) -> Self::Future {
+        // `Self::Future` is 'static, so the proxy's configuration is cloned
+        // into the returned future rather than borrowed
+        let proxy = self.clone();
+        Box::new(
+            async move {
+                // Derive per-connection isolation credentials if isolation is configured
+                let isolation_credentials = match &proxy.isolation {
+                    Some(Isolation::PerDestinationHost) => {
+                        Some(Self::derive_isolation_credentials(dst.host().as_bytes()))
+                    }
+                    None => None,
+                };
+                proxy.connect_with_credentials(dst, isolation_credentials).await
+            }
+            .boxed()
+            .compat(),
+        )
+    }
+}
+
+/// A SOCKS4/SOCKS4a proxy
+///
+/// Speaks the older SOCKS4 protocol (no method negotiation or
+/// authentication) for compatibility with proxies that don't support
+/// SOCKS5. When the destination host is a literal IPv4 address, it is
+/// encoded directly as DSTIP per plain SOCKS4; otherwise the SOCKS4a
+/// hostname extension is used to have the proxy resolve the name
+pub struct Socks4aProxy {
+    addr: SocketAddr,
+}
+
+impl Socks4aProxy {
+    /// The SOCKS version this object supports
+    const VER: u8 = 4;
+    /// The CONNECT command
+    const CMD_CONNECT: u8 = 0x01;
+    /// Constructor for the proxy
+    ///
+    /// # Parameters
+    /// * `addr` - address of the proxy
+    pub fn new(addr: SocketAddr) -> Self {
+        Socks4aProxy { addr }
+    }
+}
+
+impl Connect for Socks4aProxy {
     /// The underlying transport uses TCP
     type Transport = TcpStream;
     /// Uses `std::io::Error`
     type Error = io::Error;
-    /// Boxes make things simpler
-    type Future = Box<Future<Item = (TcpStream, Connected), Error = io::Error> + Send>;
+    /// `hyper::client::connect::Connect` still speaks futures 0.1, so the
+    /// async/await body is boxed and bridged back to a futures 0.1 future
+    /// via `futures::compat`
+    type Future = Box<dyn Future<Item = (TcpStream, Connected), Error = io::Error> + Send>;
     /// Connects to the destination through the proxy
     /// # Parameters
     /// * `dst` - the destination to connect to
     fn connect(&self, dst: Destination) -> Self::Future {
-        // Connect to the proxy
-        let handshake = TcpStream::connect(&self.addr)
-            // Send supported methods and receive a method/version back
-            .and_then(|socket| Self::method_handshake(socket))
-            // Check the method and version
-            .and_then(|(socket, version, method)| match (version, method) {
-                // No authentication 
-                (Self::VER, Socks5Method::NoAuthRequired) => Ok(socket),
-                // TODO: user/pass auth and GSSAPI
-                // Specific error for when no acceptable methods are returned
-                (Self::VER, Socks5Method::NoAcceptable) => Err(error::no_acceptable_methods()),
-                // Unsupported method
-                (Self::VER, method) => Err(error::unsupported_method(method)),
-                // Unsupported SOCKS version
-                (version, _) => Err(error::unsupported_version(version))
-            })
-            // Send the connection request
-            .and_then(move |socket| {
+        // `Self::Future` is 'static, so the proxy's address is copied into
+        // the returned future rather than borrowed
+        let addr = self.addr;
+        Box::new(
+            async move {
+                // Connect to the proxy
+                let socket = TcpStream::connect(&addr).compat().await?;
                 // Initialize the request with known values
-                let mut request: Vec<u8> = vec![Self::VER, 0x01, Self::RSV];
-                // Try to parse the destination as an IP address
-                match IpAddr::from_str(dst.host()) {
-                    // If the parsing works
-                    Ok(ip) => match ip  {
-                        IpAddr::V4(ip) => {
-                            request.push(0x01);
-                            request.extend_from_slice(&ip.octets());
-                        },
-                        IpAddr::V6(ip) => {
-                            request.push(0x04);
-                            request.extend_from_slice(&ip.octets());
-                        }
-                    },
-                    // If the parsing fails, treat the
-                    // destination as a hostname
-                    Err(_) => {
-                        request.push(0x03);
-                        // Extract the hostname from the destination
-                        let host = dst.host();
-                        // Ensure the host's length is compliant
-                        let length: u8 = match host.len().try_into() {
-                            // Zero-length or too long
-                            Ok(0) | Err(_) => 
-                                return Err(error::invalid_host_length(host.len())),
-                            // Normal case
-                            Ok(length) => length,
-                        };
-                        // Add the length byte to the request
-                        request.push(length);
-                        // Add the hostname as bytes to the request
-                        request.extend(host.bytes());
-                    }
-                };
+                let mut request: Vec<u8> = vec![Self::VER, Self::CMD_CONNECT];
                 // Get the port
                 let port = match dst.port() {
                     Some(port) => port,
-                    // If the port is not specified, use
-                    // the scheme to determine it
+                    // If the port is not specified, use the scheme to determine it
                     None => match dst.scheme() {
                         "http" => 80,
                         "https" => 443,
-                        scheme => return Err(error::unsupported_scheme(scheme))
-                    }
+                        scheme => return Err(error::unsupported_scheme(scheme)),
+                    },
                 };
                 // Add the port
-                request.extend_from_slice(
-                    &port
-                        .to_be()
-                        .to_bytes()
-                );
-                // Write the request over the socket 
-                Ok(write_all(socket, request).map(|(socket, _)| socket))
-            })
-            // Result here is a future of a future, so we need to flatten it
-            .flatten()
-            // Read in the first part of the response
-            // VER, REP, RSV, ATYP are the same size in all responses
-            .and_then(|socket| {
-                read_exact(socket, [0x00; 4])
-            })
-            // Verify the version, reply, and reserved byte
-            .and_then(|(socket, response)| {
-                // Check version
-                if response[0] != Self::VER {
-                    return Err(error::unsupported_version(response[0]))
+                request.extend_from_slice(&port.to_be_bytes());
+                // Extract the destination host
+                let host = dst.host();
+                // If the host is already a literal IP address, use plain SOCKS4
+                // framing: encode the real DSTIP and skip the hostname trailer.
+                // Otherwise, fall back to the SOCKS4a extension: an impossible
+                // IP address (0.0.0.x, x != 0) signals to the proxy that a
+                // hostname follows the userid
+                match IpAddr::from_str(host) {
+                    Ok(IpAddr::V4(ip)) => {
+                        request.extend_from_slice(&ip.octets());
+                        // This client has no userid to offer
+                        request.push(0x00);
+                    }
+                    Ok(IpAddr::V6(_)) => return Err(error::socks4_ipv6_unsupported()),
+                    Err(_) => {
+                        request.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+                        // This client has no userid to offer
+                        request.push(0x00);
+                        // The hostname is null-terminated, so it cannot contain a null byte
+                        if host.bytes().any(|b| b == 0x00) {
+                            return Err(error::invalid_host_length(host.len()));
+                        }
+                        // Add the hostname, null-terminated
+                        request.extend(host.bytes());
+                        request.push(0x00);
+                    }
                 }
-                // Check the reply code
-                let reply = response[1].into();
-                if reply != Socks5Reply::Succeeded {
-                    return Err(error::reply_error(reply))
+                // Write the request over the socket
+                let (socket, _) = write_all(socket, request).compat().await?;
+                // Read in the reply: NULL(0x00), CD, DSTPORT(2), DSTIP(4)
+                let (socket, response) = read_exact(socket, [0x00; 8]).compat().await?;
+                // The first byte is always null, not a version number
+                if response[0] != 0x00 {
+                    return Err(error::invalid_reserved(response[0]));
                 }
-                // Check reserved byte
-                if response[2] != Self::RSV { 
-                    return Err(error::invalid_reserved(response[2]))
+                match response[1] {
+                    90 => Ok((socket, Connected::new())),
+                    91 => Err(error::socks4_request_rejected()),
+                    92 => Err(error::socks4_identd_unreachable()),
+                    93 => Err(error::socks4_identd_mismatch()),
+                    cd => Err(error::invalid_socks4_reply(cd)),
                 }
-                // TODO: Check address type is known
-                // Return the socket and address type
-                Ok((socket, response[3]))
-            })
-            // Read in the address
-            .and_then(|(socket, atyp)| {
-                let address_future: Box<Future<Item=TcpStream, Error=io::Error> + Send> = match atyp {
-                    // IPv4
-                    0x01 => {
-                        // Create the future to read an IPV4 address
-                        let fut = read_exact(socket, [0x00; 4])
-                            .map(|(socket, _)| socket);
-                        // Box it
-                        Box::new(fut)
-                    },
-                    // Hostname
-                    0x03 => {
-                        // Create the future to read the hostname
-                        let fut = read_exact(socket, [0x00; 1])
-                            .and_then(|(socket, len)|
-                                read_exact(socket, vec![0x00; len[0] as usize])
-                            )
-                            .map(|(socket, _)| socket);
-                        // Box it
-                        Box::new(fut)
-                    },
-                    // Ipv6
-                    0x04 => {
-                        // Create the future to read an IPV6 address
-                        let fut = read_exact(socket, [0x00; 16])
-                            .map(|(socket, _)| socket);
-                        // Box it
-                        Box::new(fut)
-                    }
-                    // Invalid values
-                    atyp => {
-                        // Create an error
-                        let err = error::invalid_address_type(atyp);
-                        // Box it
-                        Box::new(future::err(err))
-                    }
-                };
-                address_future
-            })
-            // Read the port
-            .and_then(|socket| read_exact(socket, [0x00; 2]))
-            // Strip down to only the socket and something
-            // indicating the connection was successful
-            .map(|(socket, _)| (socket, Connected::new()));
-        // Box up the handshake
-        Box::new(handshake)
+            }
+            .boxed()
+            .compat(),
+        )
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::env;
     use std::io::{self, Write};
 
     use hyper;
-    use hyper::rt::{self, Future, Stream};
+    use hyper::rt::{self, Future as Future01, Stream};
     use hyper::Client;
     /// Tests the client using an existing local proxy on port 8080
     #[test]
@@ -238,7 +703,42 @@ mod test {
         rt::run(fetch_url(dst_addr));
     }
 
-    fn fetch_url(url: hyper::Uri) -> impl Future<Item = (), Error = ()> {
+    #[test]
+    fn with_auth_rejects_empty_credentials() {
+        let proxy_addr = "127.0.0.1:1080".parse().unwrap();
+        assert!(Socks5hProxy::with_auth(proxy_addr, "", "password").is_err());
+        assert!(Socks5hProxy::with_auth(proxy_addr, "username", "").is_err());
+    }
+
+    #[test]
+    fn with_auth_rejects_oversized_credentials() {
+        let proxy_addr = "127.0.0.1:1080".parse().unwrap();
+        let too_long = vec![b'a'; 256];
+        assert!(Socks5hProxy::with_auth(proxy_addr, too_long.clone(), "password").is_err());
+        assert!(Socks5hProxy::with_auth(proxy_addr, "username", too_long).is_err());
+    }
+
+    #[test]
+    fn with_auth_accepts_valid_credentials() {
+        let proxy_addr = "127.0.0.1:1080".parse().unwrap();
+        assert!(Socks5hProxy::with_auth(proxy_addr, "username", "password").is_ok());
+    }
+
+    #[test]
+    fn derive_isolation_credentials_is_deterministic() {
+        let a = Socks5hProxy::derive_isolation_credentials(b"example.com");
+        let b = Socks5hProxy::derive_isolation_credentials(b"example.com");
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn derive_isolation_credentials_differs_per_key() {
+        let a = Socks5hProxy::derive_isolation_credentials(b"example.com");
+        let b = Socks5hProxy::derive_isolation_credentials(b"example.org");
+        assert_ne!(a, b);
+    }
+
+    fn fetch_url(url: hyper::Uri) -> impl Future01<Item = (), Error = ()> {
         let proxy_addr = "192.168.0.9:8080".parse().unwrap();
         let proxy = Socks5hProxy::new(proxy_addr);
         let client: Client<Socks5hProxy, hyper::Body> = Client::builder().build(proxy);